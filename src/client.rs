@@ -12,7 +12,16 @@ use crossterm::{
     terminal, QueueableCommand,
 };
 
-use std::io::{self, stdout, Write};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use std::collections::VecDeque;
+use std::io::{self, stdout, Read, Write};
 
 use std::net::{TcpListener, TcpStream};
 use std::time::Duration;
@@ -95,24 +104,174 @@ impl Prompt {
     }
 }
 
-#[derive(Default)]
+// Encrypts the client's outgoing frames once a `/connect --secure` handshake
+// has completed. This only protects the client-to-server hop: the server
+// decrypts on the way in and rebroadcasts to every other client as plaintext,
+// so this is not end-to-end encryption between chat participants.
+struct SecureChannel {
+    cipher: ChaCha20Poly1305,
+    nonce_counter: u64,
+}
+
+impl SecureChannel {
+    fn handshake(stream: &mut TcpStream) -> io::Result<Self> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        stream.write_all(public.as_bytes())?;
+
+        let mut their_bytes = [0u8; 32];
+        stream.read_exact(&mut their_bytes)?;
+
+        let shared = secret.diffie_hellman(&PublicKey::from(their_bytes));
+        if !shared.was_contributory() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer's public key produced a non-contributory (degenerate) shared secret",
+            ));
+        }
+
+        let key = Sha256::digest(shared.as_bytes());
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        Ok(Self { cipher, nonce_counter: 0 })
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        self.nonce_counter += 1;
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes[4..].copy_from_slice(&self.nonce_counter.to_be_bytes());
+
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+            .expect("chacha20poly1305 encryption with a 12-byte nonce cannot fail");
+
+        let mut frame = nonce_bytes.to_vec();
+        frame.extend_from_slice(&ciphertext);
+        frame
+    }
+}
+
+fn write_secure_frame(stream: &mut TcpStream, channel: &mut SecureChannel, plaintext: &[u8]) -> io::Result<()> {
+    let frame = channel.encrypt(plaintext);
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(&frame)
+}
+
+struct LogItem {
+    message: String,
+    color: Color,
+    author: Option<String>,
+    timestamp: String,
+}
+
+const DEFAULT_MAX_HISTORY: usize = 1000;
+
 struct ChatLog {
-    items: Vec<(String, Color)>,
+    items: VecDeque<LogItem>,
+    max_items: usize,
+    // Lines scrolled up from the bottom; 0 keeps the view pinned to the
+    // newest message.
+    scroll: usize,
+}
+
+impl Default for ChatLog {
+    fn default() -> Self {
+        Self {
+            items: VecDeque::new(),
+            max_items: DEFAULT_MAX_HISTORY,
+            scroll: 0,
+        }
+    }
 }
 
 impl ChatLog {
+    fn push(&mut self, item: LogItem) {
+        self.items.push_back(item);
+        while self.items.len() > self.max_items {
+            self.items.pop_front();
+        }
+    }
+
     fn insert(&mut self, message: String, color: Color) {
-        self.items.push((message, color))
+        self.push(LogItem {
+            message,
+            color,
+            author: None,
+            timestamp: current_timestamp(),
+        })
+    }
+
+    fn insert_from(&mut self, author: String, message: String) {
+        let color = nick_color(&author);
+        self.push(LogItem {
+            message,
+            color,
+            author: Some(author),
+            timestamp: current_timestamp(),
+        })
     }
 
-    fn render(&self, buffer: &mut TerminalBuffer, x: usize, y: usize) {
-        for (dy, (message, color)) in self.items.iter().enumerate() {
-            let message_chars: Vec<_> = message.chars().collect();
-            buffer.put_cells(&message_chars, x, y + dy, *color, Color::Black);
+    fn scroll_up(&mut self, lines: usize, height: usize) {
+        let max_scroll = self.items.len().saturating_sub(height);
+        self.scroll = (self.scroll + lines).min(max_scroll);
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    fn render(&self, buffer: &mut TerminalBuffer, x: usize, y: usize, height: usize) {
+        let bottom = self.items.len().saturating_sub(self.scroll);
+        let top = bottom.saturating_sub(height);
+
+        for (dy, item) in self.items.iter().skip(top).take(bottom - top).enumerate() {
+            let prefix_chars: Vec<_> = format!("[{}] ", item.timestamp).chars().collect();
+            buffer.put_cells(&prefix_chars, x, y + dy, Color::DarkGrey, Color::Black);
+
+            let body = match &item.author {
+                Some(author) => format!("{author}: {message}", message = item.message),
+                None => item.message.clone(),
+            };
+            let body_chars: Vec<_> = body.chars().collect();
+            buffer.put_cells(&body_chars, x + prefix_chars.len(), y + dy, item.color, Color::Black);
         }
     }
 }
 
+fn current_timestamp() -> String {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (h, m, s) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+    format!("{h:02}:{m:02}:{s:02}")
+}
+
+fn nick_color(name: &str) -> Color {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    const PALETTE: &[Color] = &[
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Blue,
+        Color::Magenta,
+        Color::Cyan,
+        Color::DarkRed,
+        Color::DarkGreen,
+        Color::DarkYellow,
+        Color::DarkBlue,
+        Color::DarkMagenta,
+        Color::DarkCyan,
+    ];
+
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    PALETTE[hasher.finish() as usize % PALETTE.len()]
+}
+
 fn status_bar(buffer: &mut TerminalBuffer, label: &str, x: usize, y: usize, w: usize) {
     let label_chars: Vec<_> = label.chars().collect();
     let n = std::cmp::min(label_chars.len(), w);
@@ -127,39 +286,129 @@ fn status_bar(buffer: &mut TerminalBuffer, label: &str, x: usize, y: usize, w: u
 #[derive(Default)]
 struct Client {
     stream: Option<TcpStream>,
+    secure: Option<SecureChannel>,
     chat: ChatLog,
     quit: bool,
+    name: Option<String>,
+    read_buf: String,
+    online_count: Option<usize>,
 }
 
-fn connect_command(client: &mut Client, argument: &str) {
-    let ip = argument;
-    if client.stream.is_none() {
-        client.stream = TcpStream::connect(&format!("{ip}:6969")).and_then(|mut stream| {
-            stream.set_nonblocking(true)?;
-            Ok(stream)
-        })
-        .map_err(|err| {
-            chat_error!(&mut client.chat, "Could not connect to {ip}: {err}");
+// Splits a trailing `--secure` flag off of a `/connect` argument, e.g.
+// `/connect 127.0.0.1 --secure`.
+fn parse_connect_argument(argument: &str) -> (String, bool) {
+    let mut secure = false;
+    let ip: Vec<&str> = argument
+        .split_whitespace()
+        .filter(|token| {
+            if *token == "--secure" {
+                secure = true;
+                false
+            } else {
+                true
+            }
         })
-        .ok();
-    } else {
+        .collect();
+    (ip.join(" "), secure)
+}
+
+fn connect_command(client: &mut Client, argument: &str) {
+    let (ip, secure) = parse_connect_argument(argument);
+
+    if client.stream.is_some() {
         chat_error!(&mut client.chat, "You are already connected to a server. Disconnect with /disconnect first.");
+        return;
+    }
+
+    let connected = TcpStream::connect(format!("{ip}:6969")).and_then(|mut stream| {
+        let channel = if secure {
+            stream.write_all(b"S")?;
+            Some(SecureChannel::handshake(&mut stream)?)
+        } else {
+            stream.write_all(b"P")?;
+            None
+        };
+        stream.set_nonblocking(true)?;
+        Ok((stream, channel))
+    });
+
+    match connected {
+        Ok((stream, channel)) => {
+            client.stream = Some(stream);
+            client.secure = channel;
+            if secure {
+                chat_info!(&mut client.chat, "Outgoing messages to {ip} are now encrypted. The server still broadcasts them in the clear to other clients.");
+            }
+        }
+        Err(err) => {
+            chat_error!(&mut client.chat, "Could not connect to {ip}: {err}");
+        }
+    }
+
+    if let Some(name) = client.name.clone() {
+        send_handshake(client, &name);
     }
 }
 
 fn disconnect_command(client: &mut Client, _argument: &str) {
     if client.stream.is_some() {
+        let _ = send_line(client, "BYE");
         client.stream = None;
+        client.secure = None;
+        client.online_count = None;
         chat_info!(&mut client.chat, "Disconnected.");
     } else {
         chat_info!(&mut client.chat, "You are already offline. To connect use /connect <ip>");
     }
 }
 
-fn quit_command(client: &mut Client, _argument: &str) {
+fn quit_command(client: &mut Client, argument: &str) {
+    disconnect_command(client, argument);
     client.quit = true;
 }
 
+// Writes a single framed line, going through the secure channel if one was
+// negotiated at connect time and plain `line\n` otherwise.
+fn send_line(client: &mut Client, line: &str) -> io::Result<()> {
+    let Some(ref mut stream) = client.stream else {
+        return Ok(());
+    };
+
+    match client.secure.as_mut() {
+        Some(channel) => write_secure_frame(stream, channel, line.as_bytes()),
+        None => stream.write_all(format!("{line}\n").as_bytes()),
+    }
+}
+
+fn send_handshake(client: &mut Client, name: &str) {
+    let _ = send_line(client, &format!("NAME {name}"));
+}
+
+fn clients_command(client: &mut Client, _argument: &str) {
+    if client.stream.is_some() {
+        let _ = send_line(client, "LIST");
+    } else {
+        chat_info!(&mut client.chat, "You are offline. Use /connect <ip> to connect to a server.");
+    }
+}
+
+fn nick_command(client: &mut Client, argument: &str) {
+    let name = argument.trim();
+    if name.is_empty() {
+        chat_error!(&mut client.chat, "Usage: /nick <name>");
+        return;
+    }
+
+    if name.contains(|ch: char| ch.is_whitespace() || ch == ',') {
+        chat_error!(&mut client.chat, "Nicknames can't contain spaces or commas.");
+        return;
+    }
+
+    client.name = Some(name.to_string());
+    send_handshake(client, name);
+    chat_info!(&mut client.chat, "You are now known as {name}.");
+}
+
 fn help_command(client: &mut Client, argument: &str) {
     let name = argument.trim();
     if name.is_empty() {
@@ -187,8 +436,8 @@ const COMMANDS: &[Command] = &[
     Command {
         name: "connect",
         run: connect_command,
-        description: "Connect to a server by <ip>",
-        signature: "/connect <ip>",
+        description: "Connect to a server by <ip>, optionally with --secure to encrypt the messages you send (the server still rebroadcasts them in the clear)",
+        signature: "/connect <ip> [--secure]",
     },
     Command {
         name: "disconnect",
@@ -208,12 +457,70 @@ const COMMANDS: &[Command] = &[
         description: "Print help",
         signature: "/help [command]",
     },
+    Command {
+        name: "nick",
+        run: nick_command,
+        description: "Set your display name",
+        signature: "/nick <name>",
+    },
+    Command {
+        name: "clients",
+        run: clients_command,
+        description: "List users currently online",
+        signature: "/clients",
+    },
 ];
 
 fn find_command(name: &str) -> Option<&Command> {
     COMMANDS.iter().find(|command| command.name == name)
 }
 
+fn poll_incoming(client: &mut Client) {
+    let mut buf = [0u8; 1024];
+    let mut disconnected = None;
+
+    if let Some(ref mut stream) = client.stream {
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => {
+                    disconnected = Some("Server closed the connection.".to_string());
+                    break;
+                }
+                Ok(n) => client.read_buf.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    disconnected = Some(format!("Connection error: {err}"));
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(notice) = disconnected {
+        client.stream = None;
+        chat_error!(&mut client.chat, "{notice}");
+    }
+
+    while let Some(pos) = client.read_buf.find('\n') {
+        let line: String = client.read_buf.drain(..=pos).collect();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix("MSG ") {
+            let (author, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            client.chat.insert_from(author.to_string(), message.to_string());
+        } else if let Some(roster) = line.strip_prefix("ROSTER ") {
+            let names: Vec<&str> = roster.split(',').filter(|name| !name.is_empty()).collect();
+            client.online_count = Some(names.len());
+            chat_info!(&mut client.chat, "Online ({}): {}", names.len(), names.join(", "));
+        } else {
+            chat_msg!(&mut client.chat, "{line}");
+        }
+    }
+}
+
 fn parse_prompt(prompt: &[char]) -> Option<(&[char], &[char])> {
     let prompt = prompt.strip_prefix(&['/'])?;
     let mut iter = prompt.splitn(2, |x| *x == ' ');
@@ -235,6 +542,9 @@ fn main() -> io::Result<()> {
     let mut screen_buffer = TerminalBuffer::new(w as usize, h as usize);
     let mut prev_screen_buffer = TerminalBuffer::new(w as usize, h as usize);
 
+    // Chat log occupies every row except the two status bars and the prompt.
+    let chat_height = h.checked_sub(3).map(|v| v as usize).unwrap_or(0).max(1);
+
     prev_screen_buffer.flush(&mut stdout)?;
     while !client.quit {
         if poll(Duration::ZERO)? {
@@ -247,6 +557,8 @@ fn main() -> io::Result<()> {
                                     'c' => quit_command(&mut client, ""),
                                     'h' => prompt.cursor_move_left(),
                                     'l' => prompt.cursor_move_right(),
+                                    'u' => client.chat.scroll_up(chat_height / 2 + 1, chat_height),
+                                    'd' => client.chat.scroll_down(chat_height / 2 + 1),
                                     _ => (),
                                 }
                             } else {
@@ -263,9 +575,9 @@ fn main() -> io::Result<()> {
                                     chat_error!(&mut client.chat, "Unknown command `/{command_name}`");
                                 }
                             } else {
-                                if let Some(ref mut stream) = &mut client.stream {
+                                if client.stream.is_some() {
                                     let prompt: String = prompt.data.iter().collect();
-                                    stream.write(prompt.as_bytes())?;
+                                    send_line(&mut client, &format!("MSG {prompt}"))?;
                                     chat_msg!(&mut client.chat, "{text}", text=&prompt);
                                 } else {
                                     chat_info!(
@@ -295,6 +607,12 @@ fn main() -> io::Result<()> {
                         KeyCode::Esc => {
                             prompt.clear();
                         }
+                        KeyCode::PageUp => {
+                            client.chat.scroll_up(chat_height, chat_height);
+                        }
+                        KeyCode::PageDown => {
+                            client.chat.scroll_down(chat_height);
+                        }
                         _ => (),
                     }
                 }
@@ -303,14 +621,20 @@ fn main() -> io::Result<()> {
             }
         }
 
+        poll_incoming(&mut client);
+
         screen_buffer.clear();
 
         status_bar(&mut screen_buffer, "simple-chat", 0, 0, w.into());
 
-        client.chat.render(&mut screen_buffer, 0, 1);
+        client.chat.render(&mut screen_buffer, 0, 1, chat_height);
 
         if let Some(y) = h.checked_sub(2) {
-            status_bar(&mut screen_buffer, "Online", 0, y.into(), w.into())
+            let online_label = match client.online_count {
+                Some(n) => format!("Online: {n}"),
+                None => "Online".to_string(),
+            };
+            status_bar(&mut screen_buffer, &online_label, 0, y.into(), w.into())
         }
 
         if let Some(y) = h.checked_sub(1) {
@@ -1,20 +1,160 @@
 use std::net::{TcpListener, TcpStream, SocketAddr};
-use std::io::{Write, Read};
+use std::io::{self, Read, Write, BufRead, BufReader};
 use std::result;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::sync::Arc;
 use std::collections::HashMap;
 
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
 type Result<T> = result::Result<T, ()>;
 
+// Decrypts a connecting client's outgoing frames once it has opted into
+// `/connect --secure`. This only protects the client-to-server hop: the
+// server's own replies, and every rebroadcast of a secure client's messages
+// to other clients, keep travelling in the clear, so the chat as a whole is
+// not end-to-end encrypted.
+struct SecureChannel {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SecureChannel {
+    fn handshake(reader: &mut impl Read, stream: &TcpStream) -> io::Result<Self> {
+        let mut their_bytes = [0u8; 32];
+        reader.read_exact(&mut their_bytes)?;
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let mut writer = stream;
+        writer.write_all(public.as_bytes())?;
+
+        let shared = secret.diffie_hellman(&PublicKey::from(their_bytes));
+        if !shared.was_contributory() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer's public key produced a non-contributory (degenerate) shared secret",
+            ));
+        }
+
+        let key = Sha256::digest(shared.as_bytes());
+        Ok(Self { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+
+    fn decrypt(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 12 {
+            return None;
+        }
+        let (nonce_bytes, ciphertext) = frame.split_at(12);
+        self.cipher.decrypt(Nonce::from_slice(nonce_bytes), ciphertext).ok()
+    }
+}
+
+// A chat line has no business being anywhere near this big; treating a
+// larger declared length as a protocol error bounds the allocation below
+// instead of trusting a peer-supplied length prefix outright.
+const MAX_SECURE_FRAME_LEN: usize = 8 * 1024;
+
+// Reads one `nonce || ciphertext || tag` frame, length-prefixed by a 4-byte
+// big-endian length. `Ok(None)` means the frame was shorter than a nonce or
+// its Poly1305 tag didn't verify, and it was dropped; the connection stays open.
+fn read_secure_frame(reader: &mut impl Read, channel: &SecureChannel) -> io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_SECURE_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("secure frame length {len} exceeds the {MAX_SECURE_FRAME_LEN}-byte limit"),
+        ));
+    }
+
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame)?;
+    Ok(channel.decrypt(&frame))
+}
+
+// A chat line has no business being anywhere near this big either; a peer
+// that never sends `\n` would otherwise grow `line` without bound.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+// Reads one `\n`-terminated line, failing once more than `max_len` bytes have
+// been buffered without a newline. The check runs per filled chunk, so the
+// buffer can exceed `max_len` by up to one chunk, and a line whose newline
+// arrives in that chunk is returned whatever its length.
+// `Ok(None)` means the peer closed the connection before sending anything.
+fn read_bounded_line(reader: &mut impl BufRead, max_len: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(if line.is_empty() {
+                None
+            } else {
+                Some(String::from_utf8_lossy(&line).into_owned())
+            });
+        }
+
+        let end = available.iter().position(|&b| b == b'\n').map_or(available.len(), |pos| pos + 1);
+        line.extend_from_slice(&available[..end]);
+        reader.consume(end);
+
+        if line.last() == Some(&b'\n') {
+            return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+        }
+
+        if line.len() > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line length exceeds the {max_len}-byte limit"),
+            ));
+        }
+    }
+}
+
 enum Message {
     ClientConnected { address: SocketAddr, stream: Arc<TcpStream> },
     ClientDisconnected { address: SocketAddr },
-    NewMessage { author: SocketAddr, message: String },
+    ClientRenamed { address: SocketAddr, name: String },
+    NewMessage { address: SocketAddr, name: String, message: String },
+    ListClients { requester: SocketAddr },
+}
+
+type Clients = HashMap<SocketAddr, (String, Arc<TcpStream>)>;
+
+// Writes `bytes` to every client other than `author`, removing any client
+// whose write fails and letting the remaining clients know they left. A
+// single broken pipe must never propagate up and take the whole server
+// thread down with it.
+fn broadcast(clients: &mut Clients, author: SocketAddr, bytes: &[u8]) {
+    let mut dead = Vec::new();
+
+    for (addr, (_, stream)) in clients.iter() {
+        if *addr != author {
+            if let Err(err) = stream.as_ref().write(bytes) {
+                eprintln!("ERROR: could not send message to {addr}: {err}");
+                dead.push(*addr);
+            }
+        }
+    }
+
+    for addr in dead {
+        if let Some((name, _)) = clients.remove(&addr) {
+            println!("INFO: client {name} ({addr}) dropped: broken pipe.");
+            let notice = format!("* {name} left the chat (broken pipe)\n");
+            broadcast(clients, addr, notice.as_bytes());
+        }
+    }
 }
 
 fn server(receiver: Receiver<Message>) -> Result<()> {
-    let mut clients: HashMap<SocketAddr, Arc<TcpStream>> = HashMap::new();
+    let mut clients: Clients = HashMap::new();
     loop {
         let message = receiver.recv().map_err(|err| {
             eprintln!("ERROR: connection hang up: {err}");
@@ -23,26 +163,36 @@ fn server(receiver: Receiver<Message>) -> Result<()> {
         match message {
             Message::ClientConnected { address, stream } => {
                 println!("INFO: client {address} is now connected.");
-                stream.as_ref().write(b"You are connected to awesome server.").map_err(|err| {
+                let _ = stream.as_ref().write(b"You are connected to awesome server.").map_err(|err| {
                     eprintln!("ERROR: could not send message to {address}: {err}");
-                })?;
-                clients.insert(address, stream);
+                });
+                clients.insert(address, (address.to_string(), stream));
             },
             Message::ClientDisconnected { address } => {
-                if let Some(stream) = clients.remove(&address) {
-                    println!("INFO: client {address} was disconnected.");
-                    stream.as_ref().write(b"You are disconnected.").map_err(|err| {
+                if let Some((name, stream)) = clients.remove(&address) {
+                    println!("INFO: client {name} ({address}) was disconnected.");
+                    let _ = stream.as_ref().write(b"You are disconnected.").map_err(|err| {
                         eprintln!("ERROR: could not send message to {address}: {err}");
-                    })?;
+                    });
                 }
             },
-            Message::NewMessage { author, message } => {
-                for (addr, stream) in clients.iter() {
-                    if *addr != author {
-                        stream.as_ref().write(message.as_bytes()).map_err(|err| {
-                            eprintln!("ERROR: could not send message to {addr}: {err}");
-                        })?;  
-                    }
+            Message::ClientRenamed { address, name } => {
+                if let Some(client) = clients.get_mut(&address) {
+                    client.0 = name;
+                }
+            },
+            Message::NewMessage { address, name, message } => {
+                let line = format!("MSG {name} {message}\n");
+                broadcast(&mut clients, address, line.as_bytes());
+            },
+            Message::ListClients { requester } => {
+                if let Some((_, stream)) = clients.get(&requester) {
+                    let mut names: Vec<&str> = clients.values().map(|(name, _)| name.as_str()).collect();
+                    names.sort();
+                    let roster = format!("ROSTER {}\n", names.join(","));
+                    let _ = stream.as_ref().write(roster.as_bytes()).map_err(|err| {
+                        eprintln!("ERROR: could not send roster to {requester}: {err}");
+                    });
                 }
             },
         }
@@ -61,29 +211,95 @@ fn client(stream: Arc<TcpStream>, sender: Sender<Message>) -> Result<()> {
     sender.send(Message::ClientConnected { address, stream: stream.clone() }).map_err(|err| {
         eprintln!("ERROR: could not send message ClientConnected to server: {err}");
     })?;
-   
-    let mut buff = [0; 64];
-    loop {
-        let n = stream.as_ref().read(&mut buff).map_err(|err| {
-            eprintln!("ERROR: could not read data from {address}: {err}");
+
+    // Line-delimited frames: `NAME <nick>`, `MSG <text>`, `BYE`. A client
+    // that opted into `/connect --secure` sends a leading `S` byte and an
+    // X25519 public key before any of those, and every frame afterwards is
+    // `nonce || ciphertext || tag`, length-prefixed instead of newline-terminated.
+    let mut reader = BufReader::new(stream.as_ref());
+
+    let mut mode = [0u8; 1];
+    if let Err(err) = reader.read_exact(&mut mode) {
+        eprintln!("ERROR: could not read handshake mode from {address}: {err}");
+        sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+            eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
         })?;
+        return Ok(());
+    }
 
-        if n == 0 {
-            sender.send(Message::ClientDisconnected { address }).map_err(|err| {
-                eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
-            })?;
-        } else {
-            let mut mesg = String::new();
-            for c in buff.iter(){
-                if *c >= 32 {   // This removes escape keys
-                    mesg.push(*c as char);
-                }
+    let secure_channel = if &mode == b"S" {
+        match SecureChannel::handshake(&mut reader, stream.as_ref()) {
+            Ok(channel) => Some(channel),
+            Err(err) => {
+                eprintln!("ERROR: secure handshake with {address} failed: {err}");
+                sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+                    eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
+                })?;
+                return Ok(());
             }
-            sender.send(Message::NewMessage { author: address, message: mesg }).map_err(|err| {
+        }
+    } else {
+        None
+    };
+
+    let mut name = address.to_string();
+    loop {
+        let frame = match &secure_channel {
+            Some(channel) => match read_secure_frame(&mut reader, channel) {
+                Ok(Some(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+                Ok(None) => {
+                    eprintln!("WARNING: dropped frame from {address}: Poly1305 tag verification failed.");
+                    continue;
+                }
+                Err(err) => {
+                    eprintln!("ERROR: could not read data from {address}: {err}");
+                    sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+                        eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
+                    })?;
+                    break;
+                }
+            },
+            None => match read_bounded_line(&mut reader, MAX_LINE_LEN) {
+                Ok(Some(line)) => line.trim_end_matches(['\r', '\n']).to_string(),
+                Ok(None) => {
+                    sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+                        eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
+                    })?;
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("ERROR: could not read data from {address}: {err}");
+                    sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+                        eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
+                    })?;
+                    break;
+                }
+            },
+        };
+        let frame = frame.as_str();
+
+        if let Some(nick) = frame.strip_prefix("NAME ") {
+            name = nick.to_string();
+            sender.send(Message::ClientRenamed { address, name: name.clone() }).map_err(|err| {
+                eprintln!("ERROR: could not send Message::ClientRenamed to server: {err}");
+            })?;
+        } else if let Some(text) = frame.strip_prefix("MSG ") {
+            sender.send(Message::NewMessage { address, name: name.clone(), message: text.to_string() }).map_err(|err| {
                 eprintln!("ERROR: could not send Message::NewMessage to server: {err}");
             })?;
+        } else if frame == "LIST" {
+            sender.send(Message::ListClients { requester: address }).map_err(|err| {
+                eprintln!("ERROR: could not send Message::ListClients to server: {err}");
+            })?;
+        } else if frame == "BYE" {
+            sender.send(Message::ClientDisconnected { address }).map_err(|err| {
+                eprintln!("ERROR: could not send message ClientDisconnected to server: {err}");
+            })?;
+            break;
         }
     }
+
+    Ok(())
 }
 
 fn main() -> Result<()> {